@@ -1,6 +1,111 @@
+use std::collections::HashSet;
 use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 
-use teloxide::{prelude::*, update_listeners::{webhooks, UpdateListener}};
+use teloxide::{
+    adaptors::trace::{Settings as TraceSettings, Trace},
+    dispatching::UpdateFilterExt,
+    error_handlers::LoggingErrorHandler,
+    prelude::*,
+    requests::Requester,
+    update_listeners::{webhooks, UpdateListener},
+    utils::command::BotCommands,
+};
+
+/// Commands supported by this bot, parsed out of incoming messages by
+/// `filter_command::<Command>()`.
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase", description = "These commands are supported:")]
+enum Command {
+    #[command(description = "display this text")]
+    Help,
+    #[command(description = "start the bot")]
+    Start,
+    #[command(description = "check that the bot is alive")]
+    Ping,
+    #[command(description = "(admin only) show uptime and processed update count")]
+    Stats,
+}
+
+/// What an incoming update's sender is allowed to do, derived from
+/// [`AccessControl`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Role {
+    Unregistered,
+    Allowed,
+    Admin,
+}
+
+/// Allow-list configuration read from `ADMIN_IDS` and `ALLOWED_IDS`
+/// (comma-separated Telegram user IDs). Admins are implicitly allowed.
+struct AccessControl {
+    admins: HashSet<UserId>,
+    allowed: HashSet<UserId>,
+}
+
+impl AccessControl {
+    fn from_env() -> Self {
+        let admins = parse_user_ids("ADMIN_IDS");
+        let allowed = parse_user_ids("ALLOWED_IDS");
+
+        if admins.is_empty() && allowed.is_empty() {
+            log::info!("ADMIN_IDS/ALLOWED_IDS not set, access control is disabled (everyone allowed)");
+        } else {
+            log::warn!(
+                "Access control enabled: {} admin(s), {} allowed user(s)",
+                admins.len(),
+                allowed.len(),
+            );
+        }
+
+        Self { admins, allowed }
+    }
+
+    /// `Role::Unregistered` is only possible once the allow-list is
+    /// non-empty; with no configuration at all, everyone is `Allowed`,
+    /// matching the bot's behavior before access control existed.
+    fn role_of(&self, user: UserId) -> Role {
+        if self.admins.is_empty() && self.allowed.is_empty() {
+            Role::Allowed
+        } else if self.admins.contains(&user) {
+            Role::Admin
+        } else if self.allowed.contains(&user) {
+            Role::Allowed
+        } else {
+            Role::Unregistered
+        }
+    }
+}
+
+fn parse_user_ids(var: &str) -> HashSet<UserId> {
+    env::var(var)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|id| id.trim().parse::<u64>().ok())
+        .map(UserId)
+        .collect()
+}
+
+/// Bot-wide counters surfaced by the admin-only `/stats` command.
+struct Stats {
+    started_at: Instant,
+    processed: AtomicU64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            processed: AtomicU64::new(0),
+        }
+    }
+
+    fn record_update(&self) {
+        self.processed.fetch_add(1, Ordering::Relaxed);
+    }
+}
 
 #[tokio::main]
 async fn main() {
@@ -9,6 +114,22 @@ async fn main() {
 
     let bot = Bot::from_env();
 
+    if env::var("TRACE_REQUESTS").is_ok() {
+        log::info!("Request tracing enabled");
+        run(Trace::new(bot, TraceSettings::TRACE_REQUESTS_VERBOSE)).await;
+    } else {
+        run(bot).await;
+    }
+}
+
+/// Builds and runs the webhook server and dispatcher. Generic over the bot
+/// type so the same pipeline works whether `bot` is a plain `Bot` or one
+/// wrapped in `Trace` for request logging.
+async fn run<R>(bot: R)
+where
+    R: Requester<Err = teloxide::RequestError> + Clone + Send + Sync + 'static,
+    <R as Requester>::DeleteWebhook: Send,
+{
     let port: u16 = env::var("PORT")
         .unwrap_or_else(|_| "8080".to_string())
         .parse()
@@ -20,13 +141,25 @@ async fn main() {
     let url = format!("https://{host}/webhook").parse().unwrap();
     log::info!("URL: {url}");
 
+    let mut options = webhooks::Options::new(addr, url);
+    if let Ok(secret) = env::var("WEBHOOK_SECRET") {
+        options = options.secret_token(secret);
+    }
+    let expected_url = options.url.to_string();
+
     let (
         mut listener, stop_flag, router
-    ) = webhooks::axum_to_router(bot.clone(), webhooks::Options::new(addr, url))
+    ) = webhooks::axum_to_router(bot.clone(), options)
         .await
         .expect("Couldn't set up webhook");
 
-    let app = router.route("/health", axum::routing::get(health_handler));
+    let health_bot = bot.clone();
+    let app = router
+        .route("/health", axum::routing::get(health_handler))
+        .route(
+            "/health/deep",
+            axum::routing::get(move || deep_health_handler(health_bot.clone(), expected_url.clone())),
+        );
 
     let stop_token = listener.stop_token();
 
@@ -35,6 +168,10 @@ async fn main() {
             .await
             .inspect_err(|_| stop_token.stop())
             .expect("Couldn't bind to the address");
+        let local_addr = tcp_listener
+            .local_addr()
+            .expect("Couldn't read the bound local address");
+        log::info!("Listening on {local_addr}");
         axum::serve(tcp_listener, app)
             .with_graceful_shutdown(stop_flag)
             .await
@@ -42,17 +179,122 @@ async fn main() {
             .expect("Axum server error");
     });
 
-    teloxide::repl_with_listener(
-        bot,
-        |bot: Bot, msg: Message| async move {
-            bot.send_message(msg.chat.id, "pong").await?;
-            Ok(())
-        },
-        listener,
-    )
+    let access_control = Arc::new(AccessControl::from_env());
+    let stats = Arc::new(Stats::new());
+
+    let command_handler = Update::filter_message()
+        .filter_command::<Command>()
+        .filter_map(extract_role)
+        .branch(
+            dptree::filter(|role: Role| role != Role::Unregistered).endpoint(answer::<R>),
+        )
+        .branch(dptree::endpoint(reject_unregistered::<R>));
+
+    let schema = dptree::entry()
+        .branch(command_handler)
+        .branch(dptree::endpoint(default_handler));
+
+    Dispatcher::builder(bot, schema)
+        .dependencies(dptree::deps![access_control, stats])
+        .enable_ctrlc_handler()
+        .build()
+        .dispatch_with_listener(
+            listener,
+            LoggingErrorHandler::with_custom_text("An error occurred in the dispatcher"),
+        )
         .await;
 }
 
+fn extract_role(msg: Message, access_control: Arc<AccessControl>) -> Option<Role> {
+    let user_id = msg.from.as_ref()?.id;
+    Some(access_control.role_of(user_id))
+}
+
+async fn answer<R: Requester<Err = teloxide::RequestError>>(
+    bot: R,
+    msg: Message,
+    cmd: Command,
+    role: Role,
+    stats: Arc<Stats>,
+) -> ResponseResult<()> {
+    stats.record_update();
+
+    let text = match cmd {
+        Command::Help => Command::descriptions().to_string(),
+        Command::Start => "Hi! I'm up and listening for commands.".to_string(),
+        Command::Ping => "pong".to_string(),
+        Command::Stats if role == Role::Admin => format!(
+            "Uptime: {:.0?}\nProcessed updates: {}",
+            stats.started_at.elapsed(),
+            stats.processed.load(Ordering::Relaxed),
+        ),
+        Command::Stats => "This command is restricted to admins.".to_string(),
+    };
+    bot.send_message(msg.chat.id, text).await?;
+    Ok(())
+}
+
+/// Politely rejects commands from users who are neither admins nor on the
+/// allow-list.
+async fn reject_unregistered<R: Requester<Err = teloxide::RequestError>>(
+    bot: R,
+    msg: Message,
+    stats: Arc<Stats>,
+) -> ResponseResult<()> {
+    stats.record_update();
+    bot.send_message(msg.chat.id, "Sorry, you're not allowed to use this bot.")
+        .await?;
+    Ok(())
+}
+
+async fn default_handler(upd: Update, stats: Arc<Stats>) -> ResponseResult<()> {
+    stats.record_update();
+    log::warn!("Unhandled update: {upd:?}");
+    Ok(())
+}
+
 async fn health_handler() -> &'static str {
     "OK"
 }
+
+/// Queries `getWebhookInfo` and reports whether Telegram's view of the
+/// webhook matches what we expect, returning 503 if it doesn't or if
+/// Telegram reports a delivery error (`last_error_message` is set). The
+/// age of that error (`last_error_date`) is reported but not checked.
+async fn deep_health_handler<R: Requester<Err = teloxide::RequestError>>(
+    bot: R,
+    expected_url: String,
+) -> (axum::http::StatusCode, axum::Json<serde_json::Value>) {
+    let info = match bot.get_webhook_info().await {
+        Ok(info) => info,
+        Err(err) => {
+            return (
+                axum::http::StatusCode::SERVICE_UNAVAILABLE,
+                axum::Json(serde_json::json!({
+                    "error": format!("getWebhookInfo request failed: {err}"),
+                })),
+            );
+        }
+    };
+
+    let url_matches = info.url.as_ref().map(|u| u.as_str()) == Some(expected_url.as_str());
+    let has_recent_error = info.last_error_message.is_some();
+    let last_error_date = info.last_error_date.map(|d| d.timestamp());
+
+    let body = serde_json::json!({
+        "url_matches": url_matches,
+        "expected_url": expected_url,
+        "registered_url": info.url.as_ref().map(|u| u.as_str()),
+        "pending_update_count": info.pending_update_count,
+        "last_error_message": info.last_error_message,
+        "last_error_date": last_error_date,
+    });
+
+    let status = if url_matches && !has_recent_error {
+        axum::http::StatusCode::OK
+    } else {
+        axum::http::StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, axum::Json(body))
+}